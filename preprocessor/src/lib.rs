@@ -0,0 +1,613 @@
+/* Copyright (C) 2022-2024 Peter Lafreniere
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Write},
+};
+
+use binary_layout::prelude::*;
+use clap::ValueEnum;
+
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 0;
+const PATCH_VERSION: u8 = 0;
+
+#[derive(Clone, ValueEnum)]
+pub enum Calc {
+    TI89,
+    TI92P,
+    V200,
+}
+
+impl Calc {
+    /// Extension for a file holding a single packed variable.
+    pub fn var_ext(&self) -> &'static str {
+        match self {
+            Calc::TI89 => ".89y",
+            Calc::TI92P => ".9xy",
+            Calc::V200 => ".v2y",
+        }
+    }
+
+    /// Extension for a file bundling several packed variables into a group.
+    pub fn group_ext(&self) -> &'static str {
+        match self {
+            Calc::TI89 => ".89g",
+            Calc::TI92P => ".9xg",
+            Calc::V200 => ".v2g",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Variant {
+    Chip8,
+    SChip,
+    XoChip,
+}
+
+impl Variant {
+    /// Largest ROM this variant's address space can hold.
+    pub fn max_rom_size(self) -> usize {
+        match self {
+            Variant::Chip8 => 0x1000,
+            Variant::SChip => 0x1000,
+            Variant::XoChip => 0x10000,
+        }
+    }
+
+    /// Value stored in the header for the on-calc interpreter to read.
+    fn header_code(self) -> u8 {
+        match self {
+            Variant::Chip8 => 0,
+            Variant::SChip => 1,
+            Variant::XoChip => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for Variant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Variant::Chip8 => "chip8",
+            Variant::SChip => "s-chip",
+            Variant::XoChip => "xo-chip",
+        })
+    }
+}
+
+define_layout!(header_size, LittleEndian, { size: u32 });
+
+define_layout!(ch8_header, BigEndian, {
+    signature: [u8; 8],
+    fill1: u16,
+    folder: [u8; 8],
+    _desc: [u8; 40],
+    fill2: [u8; 6],
+    name: [u8; 8],
+    fill3: u32, // "type" field in strhead.h, but in this case it's filler content.
+    size: header_size::NestedView,
+    fill4: [u8; 6],
+    datasize: u16, // Checksum starts here
+    maj_ver: u8,
+    min_ver: u8,
+    patch_ver: u8,
+});
+
+static OTH_CH8: [u8; 6] = [0, b'c', b'h', b'8', 0, 0xF8];
+
+fn strncpy<const N: usize>(dest: &mut [u8; N], src: &str) {
+    for (i, b) in dest.iter_mut().enumerate() {
+        *b = match src.as_bytes().get(i) {
+            Some(v) => *v,
+            None => 0,
+        };
+    }
+}
+
+/// Temporary workaround while we wait for write_all_vectored() to be stabilized.
+/// This version guarantees that all data is written.
+fn writev<W: Write>(dest: &mut W, src: &[&[u8]]) -> Result<(), Error> {
+    for &buf in src.iter() {
+        dest.write_all(buf)?;
+    }
+    Ok(())
+}
+
+/// Links every position to the previous position sharing the same 3-byte
+/// prefix, DEFLATE-style, so the match finder can walk straight to match
+/// candidates instead of rescanning the whole window.
+///
+/// `prev[i]` is the most recent position `p < i` with `src[p..p+3] ==
+/// src[i..i+3]`, or `None` if there isn't one.
+fn build_match_chains(src: &[u8]) -> Vec<Option<usize>> {
+    let n = src.len();
+    let mut head: HashMap<[u8; 3], usize> = HashMap::new();
+    let mut prev = vec![None; n];
+
+    if n >= 3 {
+        for i in 0..=n - 3 {
+            let key = [src[i], src[i + 1], src[i + 2]];
+            prev[i] = head.insert(key, i);
+        }
+    }
+
+    prev
+}
+
+/// Same idea as `build_match_chains`, but keyed on 2-byte prefixes so the
+/// match finder can also find length-2 back-references. Those are too short
+/// to be worth indexing by 3-byte prefix, but a length-2 back-reference
+/// (always 3 encoded bytes) still beats two escaped `0xFF` literals (4
+/// encoded bytes), so they're worth finding when both source bytes are
+/// `0xFF`.
+fn build_match_chains_len2(src: &[u8]) -> Vec<Option<usize>> {
+    let n = src.len();
+    let mut head: HashMap<[u8; 2], usize> = HashMap::new();
+    let mut prev = vec![None; n];
+
+    if n >= 2 {
+        for i in 0..=n - 2 {
+            let key = [src[i], src[i + 1]];
+            prev[i] = head.insert(key, i);
+        }
+    }
+
+    prev
+}
+
+/// See the calc code for a description of the compression format/algorithm.
+///
+/// Encoding is chosen by a backward dynamic program rather than a greedy
+/// longest-match search: `dp[i]` holds the minimum number of encoded bytes
+/// needed for `src[i..]`, and `choice[i]` records whether that minimum came
+/// from emitting a literal or a back-reference of some length, so the
+/// forward emission pass just replays the optimal choices. Candidates for
+/// the back-reference are found by walking the hash chains from
+/// `build_match_chains` (and, for length-2 matches, `build_match_chains_len2`)
+/// rather than rescanning the whole window.
+pub fn compress(src: Vec<u8>) -> Vec<u8> {
+    const COMPRESS_FLAG: u8 = 0xFF;
+    const WINDOW_SIZE: usize = 1024;
+    const MAX_COMPRESS_LEN: usize = 63;
+    // Bounds how many chain links we follow per position, so a very common
+    // 3-byte prefix can't degrade the match finder back to O(window) time.
+    const MAX_CHAIN_STEPS: usize = 128;
+
+    fn push_literal(out: &mut Vec<u8>, byte: u8) {
+        if byte == COMPRESS_FLAG {
+            out.push(COMPRESS_FLAG);
+            out.push(0x00);
+        } else {
+            out.push(byte);
+        }
+    }
+
+    fn lit_cost(byte: u8) -> u32 {
+        if byte == COMPRESS_FLAG {
+            2
+        } else {
+            1
+        }
+    }
+
+    let n = src.len();
+    let prev = build_match_chains(&src);
+    let prev2 = build_match_chains_len2(&src);
+    let mut dp = vec![0u32; n + 1];
+    // Some((absolute match start, match length)) if a back-reference is cheapest.
+    let mut choice: Vec<Option<(usize, usize)>> = vec![None; n];
+
+    for i in (0..n).rev() {
+        let window_start = i.saturating_sub(WINDOW_SIZE);
+
+        let mut best_match: Option<(usize, usize)> = None;
+        let mut cand = prev[i];
+        let mut steps = 0;
+        while let Some(p) = cand {
+            if p < window_start || steps >= MAX_CHAIN_STEPS {
+                break;
+            }
+
+            let len = src[p..]
+                .iter()
+                .zip(src[i..].iter())
+                .take(MAX_COMPRESS_LEN)
+                .take_while(|(&a, &b)| a == b)
+                .count();
+
+            if best_match.is_none_or(|(_, best_len)| len > best_len) {
+                best_match = Some((p, len));
+            }
+
+            cand = prev[p];
+            steps += 1;
+        }
+
+        // The 3-byte chain can't see length-2 matches; check separately so a
+        // run like `0xFF 0xFF` can still be encoded as a 2-byte back-reference
+        // (3 bytes) instead of two escaped literals (4 bytes).
+        if best_match.is_none_or(|(_, best_len)| best_len < 2) {
+            if let Some(p) = prev2[i] {
+                if p >= window_start {
+                    let len = src[p..]
+                        .iter()
+                        .zip(src[i..].iter())
+                        .take(2)
+                        .take_while(|(&a, &b)| a == b)
+                        .count();
+
+                    if len == 2 && best_match.is_none_or(|(_, best_len)| len > best_len) {
+                        best_match = Some((p, len));
+                    }
+                }
+            }
+        }
+
+        let maxlen = best_match.map_or(0, |(_, len)| len);
+
+        let mut best_cost = lit_cost(src[i]) + dp[i + 1];
+        let mut best_choice = None;
+
+        for len in 1..=maxlen {
+            let cost = 3 + dp[i + len];
+            if cost < best_cost {
+                best_cost = cost;
+                best_choice = Some((best_match.unwrap().0, len));
+            }
+        }
+
+        dp[i] = best_cost;
+        choice[i] = best_choice;
+    }
+
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < n {
+        match choice[i] {
+            Some((p, len)) => {
+                let offset = (i - p - 1) as u16;
+                output.push(COMPRESS_FLAG);
+                output.push(((offset & 768) >> 2 | len as u16) as u8);
+                output.push(offset as u8);
+                i += len;
+            }
+            None => {
+                push_literal(&mut output, src[i]);
+                i += 1;
+            }
+        }
+    }
+
+    output
+}
+
+/// Inverse of `compress()`, used by `--verify` and as the reference decoder
+/// for the on-calc format: a literal byte, the `0xFF 0x00` escape, or a
+/// 3-byte `0xFF`-prefixed back-reference.
+pub fn decompress(src: &[u8]) -> Result<Vec<u8>, Error> {
+    const COMPRESS_FLAG: u8 = 0xFF;
+
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < src.len() {
+        if src[i] != COMPRESS_FLAG {
+            out.push(src[i]);
+            i += 1;
+            continue;
+        }
+
+        let ctrl = *src.get(i + 1).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+
+        if ctrl == 0x00 {
+            out.push(COMPRESS_FLAG);
+            i += 2;
+            continue;
+        }
+
+        let low = *src.get(i + 2).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+        let len = (ctrl & 0x3F) as usize;
+        let offset = (((ctrl & 0xC0) as u16) << 2 | low as u16) as usize;
+
+        let start = out
+            .len()
+            .checked_sub(offset + 1)
+            .ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+
+        for k in 0..len {
+            let byte = out[start + k];
+            out.push(byte);
+        }
+
+        i += 3;
+    }
+
+    Ok(out)
+}
+
+/// The per-variable fields of a `ch8_header`, grouped so `fill_header`
+/// doesn't grow a new positional parameter every time one of them changes.
+struct VarFields<'a> {
+    folder: &'a str,
+    name: &'a str,
+    desc: &'a str,
+    datasize: usize,
+    // Only the last entry in a file has an `OTH_CH8` tag and checksum
+    // following it (`Some(ext_len)`); a group's per-ROM sub-headers are
+    // immediately followed by the next sub-header instead, so they carry
+    // no trailer of their own (`None`).
+    ext_len: Option<usize>,
+}
+
+fn fill_header(
+    mut header: ch8_header::View<&mut [u8]>,
+    calc: &Calc,
+    variant: Variant,
+    fields: &VarFields,
+) -> Result<(), Error> {
+    // Fill in all the filler data:
+    header.maj_ver_mut().write(MAJOR_VERSION);
+    header.min_ver_mut().write(MINOR_VERSION);
+    header.patch_ver_mut().write(PATCH_VERSION);
+
+    header.fill1_mut().write(0x0100);
+    header
+        .fill2_mut()
+        .copy_from_slice(&[0x01, 0x00, 0x52, 0x00, 0x00, 0x00]);
+    header.fill3_mut().write(0x1C000000);
+    // The last byte was unused filler; the on-calc interpreter reads it to
+    // pick memory size and quirks for the selected CHIP-8 variant.
+    header
+        .fill4_mut()
+        .copy_from_slice(&[0xA5, 0x5A, 0x00, 0x00, 0x00, variant.header_code()]);
+
+    // Place simple values:
+    let trailer = fields.ext_len.map_or(0, |ext_len| 3 + ext_len + 3);
+    let size_trailer = fields.ext_len.map_or(0, |ext_len| 5 + ext_len);
+    let total_datasize = fields.datasize + trailer;
+    if total_datasize > u16::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "compressed variable is {total_datasize} bytes, which exceeds the \
+                 {}-byte limit of the on-calc header's datasize field",
+                u16::MAX
+            ),
+        ));
+    }
+    header.datasize_mut().write(total_datasize as u16);
+    header
+        .size_mut()
+        .size_mut()
+        .write((ch8_header::SIZE.unwrap() + fields.datasize + size_trailer) as u32);
+    header.signature_mut().copy_from_slice(
+        match calc {
+            Calc::TI89 => "**TI89**",
+            _ => "**TI92P*",
+        }
+        .as_bytes(),
+    );
+
+    // Strings.
+    strncpy(header.folder_mut(), fields.folder);
+    strncpy(header.name_mut(), fields.name);
+    strncpy(header._desc_mut(), fields.desc);
+    Ok(())
+}
+
+fn compute_checksum(data: &[&[u8]]) -> [u8; 2] {
+    data.iter()
+        .flat_map(|v| *v)
+        .fold(0u16, |a, x| a.wrapping_add((*x).into()))
+        .to_le_bytes()
+}
+
+fn build_entry_header(
+    calc: &Calc,
+    variant: Variant,
+    folder: &str,
+    name: &str,
+    desc: &str,
+    datasize: usize,
+    has_trailer: bool,
+) -> Result<[u8; 91], Error> {
+    let mut header_storage = [0u8; 91]; // sizeof(ti_header)
+    let fields = VarFields {
+        folder,
+        name,
+        desc,
+        datasize,
+        ext_len: has_trailer.then_some("ch8".len()),
+    };
+    fill_header(ch8_header::View::new(&mut header_storage), calc, variant, &fields)?;
+    Ok(header_storage)
+}
+
+/// Packs a single ROM into the bytes of a TI variable file: a `ch8_header`
+/// followed by the compressed ROM, a trailing `OTH_CH8` tag, and a checksum
+/// covering everything from the header's `datasize` field onward.
+pub fn pack(
+    rom: &[u8],
+    calc: Calc,
+    variant: Variant,
+    folder: &str,
+    name: &str,
+    desc: &str,
+) -> Result<Vec<u8>, Error> {
+    let storage = compress(rom.to_vec());
+    let header_storage =
+        build_entry_header(&calc, variant, folder, name, desc, storage.len(), true)?;
+
+    let mut out = Vec::new();
+    writev(
+        &mut out,
+        &[
+            &header_storage,
+            &storage,
+            &OTH_CH8,
+            &compute_checksum(&[
+                &header_storage[ch8_header::datasize::OFFSET..],
+                &storage,
+                &OTH_CH8,
+            ]),
+        ],
+    )
+    .expect("writing into a Vec<u8> cannot fail");
+
+    Ok(out)
+}
+
+/// Bundles several named ROMs into one TI group file: a shared group header
+/// (built the same way as a single-variable header) followed by each ROM's
+/// own per-variable sub-header and compressed payload, closed out with a
+/// single group-level checksum.
+pub fn pack_group(
+    roms: &[(&str, &[u8])],
+    calc: Calc,
+    variant: Variant,
+    folder: &str,
+    desc: &str,
+) -> Result<Vec<u8>, Error> {
+    let entries: Vec<([u8; 91], Vec<u8>)> = roms
+        .iter()
+        .map(|(name, rom)| {
+            let storage = compress(rom.to_vec());
+            // No trailer after each sub-header: only the group as a whole
+            // ends in an `OTH_CH8` tag and checksum.
+            let header_storage =
+                build_entry_header(&calc, variant, folder, name, desc, storage.len(), false)?;
+            Ok((header_storage, storage))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let entries_size: usize = entries.iter().map(|(h, data)| h.len() + data.len()).sum();
+    let group_header_storage =
+        build_entry_header(&calc, variant, folder, "group", desc, entries_size, true)?;
+
+    let mut parts: Vec<&[u8]> = Vec::with_capacity(entries.len() * 2 + 1);
+    for (header, data) in &entries {
+        parts.push(header);
+        parts.push(data);
+    }
+    parts.push(&OTH_CH8);
+
+    let mut checksum_parts = parts.clone();
+    checksum_parts.insert(0, &group_header_storage[ch8_header::datasize::OFFSET..]);
+    let checksum = compute_checksum(&checksum_parts);
+
+    let mut out = Vec::new();
+    writev(&mut out, &[&group_header_storage]).expect("writing into a Vec<u8> cannot fail");
+    writev(&mut out, &parts).expect("writing into a Vec<u8> cannot fail");
+    writev(&mut out, &[&checksum]).expect("writing into a Vec<u8> cannot fail");
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_decompress_roundtrip() {
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00; 200],
+            vec![0xFF; 200],
+            (0..=255u8).collect(),
+            (0..=255u8).cycle().take(2000).collect(),
+            b"the quick brown fox jumps over the lazy dog".to_vec(),
+        ];
+
+        for rom in cases {
+            let packed = compress(rom.clone());
+            assert_eq!(decompress(&packed).unwrap(), rom);
+        }
+    }
+
+    #[test]
+    fn compress_uses_length_2_backreferences() {
+        // Two escaped 0xFF literals cost 4 encoded bytes; a length-2
+        // back-reference to the earlier "FF FF" run costs only 3. The filler
+        // is distinct, non-repeating, non-0xFF bytes so the 3-byte chain
+        // can't mask a length-2-match regression with unrelated savings.
+        let mut rom = vec![0xFF, 0xFF, 0x01];
+        rom.extend((0u16..253).map(|x| (x % 253 + 2) as u8));
+        rom.extend([0xFF, 0xFF, 0x02]);
+
+        let packed = compress(rom.clone());
+        assert_eq!(decompress(&packed).unwrap(), rom);
+        assert_eq!(packed.len(), 262);
+    }
+
+    #[test]
+    fn pack_group_entry_headers_exclude_trailer() {
+        // All-distinct, non-0xFF bytes compress to one literal per input byte,
+        // so the encoded length of each ROM is known ahead of time.
+        let rom_a: Vec<u8> = (0..40u8).collect();
+        let rom_b: Vec<u8> = (0..20u8).collect();
+
+        let data = pack_group(
+            &[("romA", &rom_a), ("romB", &rom_b)],
+            Calc::TI89,
+            Variant::Chip8,
+            "main",
+            "",
+        )
+        .unwrap();
+
+        // The group-level header comes first; entry A's sub-header follows it.
+        let entry_a_start = ch8_header::SIZE.unwrap();
+        let header_a =
+            ch8_header::View::new(&data[entry_a_start..entry_a_start + ch8_header::SIZE.unwrap()]);
+        let datasize_a = header_a.datasize().read() as usize;
+        let size_a = header_a.size().size().read() as usize;
+
+        // No trailer between entries: datasize covers only this entry's data
+        // and size only its own header + data, so the next sub-header starts
+        // right after them.
+        assert_eq!(datasize_a, rom_a.len());
+        assert_eq!(size_a, ch8_header::SIZE.unwrap() + rom_a.len());
+
+        let entry_b_start = entry_a_start + ch8_header::SIZE.unwrap() + rom_a.len();
+        let header_b = ch8_header::View::new(
+            &data[entry_b_start..entry_b_start + ch8_header::SIZE.unwrap()],
+        );
+        assert_eq!(header_b.datasize().read() as usize, rom_b.len());
+    }
+
+    #[test]
+    fn pack_rejects_oversized_compressed_output() {
+        // XoChip allows a 64 KiB ROM; pseudo-random bytes compress poorly
+        // (almost no repeats in the window, and every 0xFF is an escaped
+        // 2-byte literal), so they still overflow the header's 16-bit
+        // datasize field even though the raw ROM fits.
+        let mut state: u32 = 0x1234_5678;
+        let rom: Vec<u8> = (0..Variant::XoChip.max_rom_size())
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+
+        let err = pack(&rom, Calc::TI89, Variant::XoChip, "main", "rom", "").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
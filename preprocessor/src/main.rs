@@ -15,32 +15,31 @@
  */
 
 use std::{
-    cmp::{min, Ordering},
     fs::File,
     io::{Error, ErrorKind, Read, Write},
 };
 
-use binary_layout::prelude::*;
-use clap::{Parser, ValueEnum};
-
-const MAJOR_VERSION: u8 = 1;
-const MINOR_VERSION: u8 = 0;
-const PATCH_VERSION: u8 = 0;
+use clap::Parser;
+use preprocessor::{compress, decompress, pack, pack_group, Calc, Variant};
 
 // TODO: Make prettier
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     // Positional
-    /// CHIP-8 ROM
-    #[clap(value_parser)]
-    file: String,
+    /// CHIP-8 ROM(s), or "-" to read a single ROM from stdin. Pass more than
+    /// one along with --group to bundle them into a single TI group file.
+    #[clap(required = true, value_parser)]
+    files: Vec<String>,
 
     /// The target calculator
     #[clap(long, short, arg_enum, value_parser)]
     calc: Calc,
 
-    /// On-calculator variable name, clipped to 8 characters (Optional)
+    /// On-calculator variable name, clipped to 8 characters (Optional). In
+    /// --group mode this only applies to a ROM read from stdin ("-"), which
+    /// has no filename to derive a name from; every other ROM's name is
+    /// still derived from its path.
     #[clap(long, short, value_parser)]
     var_name: Option<String>,
 
@@ -51,238 +50,190 @@ struct Args {
     /// The file to place output in (Optional)
     #[clap(long, short, value_parser)]
     output: Option<String>,
+
+    /// Decompress the packed ROM back and check it matches the input
+    #[clap(long, value_parser)]
+    verify: bool,
+
+    /// The CHIP-8 variant the ROM targets, which controls the accepted ROM size
+    #[clap(default_value_t = Variant::Chip8, long, arg_enum, value_parser)]
+    variant: Variant,
+
+    /// Bundle all given ROMs into a single TI group file instead of one variable per ROM
+    #[clap(long, value_parser)]
+    group: bool,
+
+    /// On-calculator variable description, clipped to 40 characters (Optional)
+    #[clap(long, short, value_parser)]
+    desc: Option<String>,
 }
 
-#[derive(Clone, ValueEnum)]
-enum Calc {
-    TI89,
-    TI92P,
-    V200,
+fn strip_rom_suffix(path: &str) -> &str {
+    let path = path.strip_suffix(".ch8").unwrap_or(path);
+    path.strip_suffix(".rom").unwrap_or(path)
 }
 
-define_layout!(header_size, LittleEndian, { size: u32 });
-
-define_layout!(ch8_header, BigEndian, {
-    signature: [u8; 8],
-    fill1: u16,
-    folder: [u8; 8],
-    _desc: [u8; 40],
-    fill2: [u8; 6],
-    name: [u8; 8],
-    fill3: u32, // "type" field in strhead.h, but in this case it's filler content.
-    size: header_size::NestedView,
-    fill4: [u8; 6],
-    datasize: u16, // Checksum starts here
-    maj_ver: u8,
-    min_ver: u8,
-    patch_ver: u8,
-});
-
-static OTH_CH8: [u8; 6] = [0, b'c', b'h', b'8', 0, 0xF8];
-
-/// (Output path, stripped input filename)
-fn get_filename(args: &Args) -> (String, String) {
-    let mut path = args.file.as_str();
-    path = path.strip_suffix(".ch8").unwrap_or(path);
-    path = path.strip_suffix(".rom").unwrap_or(path);
+/// On-calculator variable name for a ROM, derived from its filename.
+fn var_name_from_path(path: &str) -> String {
+    let path = strip_rom_suffix(path);
     let (_, file) = path.rsplit_once('/').unwrap_or(("", path));
+    file.to_string()
+}
+
+/// An output path can only be derived from an input filename; reading the
+/// ROM from stdin means the caller has to name the output explicitly.
+fn require_named_output(args: &Args, path: &str) -> Result<(), Error> {
+    if path == "-" && args.output.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--output is required when a ROM is read from stdin",
+        ));
+    }
+    Ok(())
+}
+
+/// (Output path, stripped input filename) for packing a single ROM.
+fn get_filename(args: &Args) -> Result<(String, String), Error> {
+    let path = strip_rom_suffix(&args.files[0]);
+    require_named_output(args, path)?;
 
-    (
+    Ok((
         {
             let mut x = match &args.output {
                 Some(s) => s,
                 None => path,
             }
             .to_string();
-            x.push_str(match args.calc {
-                Calc::TI89 => ".89y",
-                Calc::TI92P => ".9xy",
-                Calc::V200 => ".v2y",
-            });
+            x.push_str(args.calc.var_ext());
             x
         },
         match &args.var_name {
-            Some(s) => s,
-            None => file,
-        }
-        .to_string(),
-    )
+            Some(s) => s.clone(),
+            None => var_name_from_path(path),
+        },
+    ))
 }
 
-fn strncpy<const N: usize>(dest: &mut [u8; N], src: &str) {
-    for (i, b) in dest.iter_mut().enumerate() {
-        *b = match src.as_bytes().get(i) {
-            Some(v) => *v,
-            None => 0,
-        };
+/// On-calculator variable name for one ROM within a --group invocation. A
+/// ROM read from stdin has no filename to derive a name from, so it needs
+/// --var-name instead; every other ROM's name still comes from its path.
+fn group_entry_name(args: &Args, file: &str) -> Result<String, Error> {
+    if file == "-" {
+        return args.var_name.clone().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "--var-name is required to name a ROM read from stdin in --group mode",
+            )
+        });
     }
+    Ok(var_name_from_path(file))
 }
 
-/// Temporary workaround while we wait for write_all_vectored() to be stabilized.
-/// This version guarantees that all data is written.
-fn writev(dest: &mut File, src: &[&[u8]]) -> Result<(), Error> {
-    for &buf in src.iter() {
-        dest.write_all(buf)?;
-    }
-    Ok(())
+/// Output path for packing several ROMs into one group file.
+fn get_group_filename(args: &Args) -> Result<String, Error> {
+    let path = strip_rom_suffix(&args.files[0]);
+    require_named_output(args, path)?;
+
+    let mut x = match &args.output {
+        Some(s) => s.clone(),
+        None => path.to_string(),
+    };
+    x.push_str(args.calc.group_ext());
+    Ok(x)
 }
 
-/// See the calc code for a description of the compression format/algorithm.
-fn compress(src: Vec<u8>) -> Vec<u8> {
-    const COMPRESS_FLAG: u8 = 0xFF;
-    const WINDOW_SIZE: usize = 1024;
-    const MAX_COMPRESS_LEN: usize = 63;
-
-    fn push_literal(out: &mut Vec<u8>, byte: u8) {
-        if byte == COMPRESS_FLAG {
-            out.push(COMPRESS_FLAG);
-            out.push(0x00);
-        } else {
-            out.push(byte);
-        }
+fn read_rom(file: &str) -> Result<Vec<u8>, Error> {
+    let mut storage = Vec::new();
+    if file == "-" {
+        std::io::stdin().read_to_end(&mut storage)?;
+    } else {
+        File::open(file)?.read_to_end(&mut storage)?;
     }
+    Ok(storage)
+}
 
-    let mut output = Vec::new();
-    let mut i = 0;
-
-    while i < src.len() {
-        let window_start = i.saturating_sub(WINDOW_SIZE);
-        let window = &src[window_start..i];
-
-        let (j, len) = window
-            .iter()
-            .enumerate()
-            .filter(|(_, &x)| x == src[i])
-            .map(|(j, _)| {
-                (
-                    j,
-                    src[(j + window_start)..]
-                        .iter()
-                        .zip(src[i..].iter())
-                        .take_while(|(&a, &b)| a == b)
-                        .count(),
-                )
-            })
-            .max_by(|(_, lena), (_, lenb)| {
-                if lena >= lenb {
-                    Ordering::Greater
-                } else {
-                    Ordering::Less
-                }
-            })
-            .unwrap_or((0, 0));
-
-        let len = min(len, MAX_COMPRESS_LEN);
-
-        if 3 < src[(j + window_start)..]
-            .iter()
-            .take(len)
-            .fold(0, |a, &e| a + if e == COMPRESS_FLAG { 2 } else { 1 })
-        {
-            let offset = (window.len() - j - 1) as u16;
-            output.push(COMPRESS_FLAG);
-            output.push(((offset & 768) >> 2 | len as u16) as u8);
-            output.push(offset as u8);
-            i += len;
-        } else {
-            push_literal(&mut output, src[i]);
-            i += 1;
-        }
+fn check_rom_size(file: &str, rom: &[u8], variant: Variant) -> Result<(), Error> {
+    let max_rom_size = variant.max_rom_size();
+    if rom.len() > max_rom_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{file} is {} bytes, which exceeds the {max_rom_size}-byte limit for {variant}",
+                rom.len()
+            ),
+        ));
     }
-
-    output
+    Ok(())
 }
 
-fn fill_header(
-    mut header: ch8_header::View<&mut [u8]>,
-    calc: Calc,
-    folder: &str,
-    name: &str,
-    datasize: usize,
-    ext_len: usize,
-) -> Result<(), Error> {
-    // Fill in all the filler data:
-    header.maj_ver_mut().write(MAJOR_VERSION);
-    header.min_ver_mut().write(MINOR_VERSION);
-    header.patch_ver_mut().write(PATCH_VERSION);
-
-    header.fill1_mut().write(0x0100);
-    header
-        .fill2_mut()
-        .copy_from_slice(&[0x01, 0x00, 0x52, 0x00, 0x00, 0x00]);
-    header.fill3_mut().write(0x1C000000);
-    header
-        .fill4_mut()
-        .copy_from_slice(&[0xA5, 0x5A, 0x00, 0x00, 0x00, 0x00]);
-
-    // Place simple values:
-    header
-        .datasize_mut()
-        .write((datasize + 3 + ext_len + 3) as u16);
-    header
-        .size_mut()
-        .size_mut()
-        .write((ch8_header::SIZE.unwrap() + datasize + 5 + ext_len) as u32);
-    header.signature_mut().copy_from_slice(
-        match calc {
-            Calc::TI89 => "**TI89**",
-            _ => "**TI92P*",
-        }
-        .as_bytes(),
-    );
-
-    // Strings.
-    strncpy(header.folder_mut(), folder);
-    strncpy(header.name_mut(), name);
+/// Sanity check that `compress()` followed by `decompress()` round-trips a ROM.
+fn verify_round_trip(rom: &[u8]) -> Result<(), Error> {
+    if decompress(&compress(rom.to_vec()))? != rom {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
     Ok(())
 }
 
-fn compute_checksum(data: &[&[u8]]) -> [u8; 2] {
-    data.iter()
-        .flat_map(|v| *v)
-        .fold(0u16, |a, x| a.wrapping_add((*x).into()))
-        .to_le_bytes()
-}
+fn write_single(args: &Args) -> Result<(), Error> {
+    let (output, filename) = get_filename(args)?;
 
-fn main() -> Result<(), Error> {
-    let args = Args::parse();
+    let rom = read_rom(&args.files[0])?;
+    check_rom_size(&args.files[0], &rom, args.variant)?;
+    if args.verify {
+        verify_round_trip(&rom)?;
+    }
 
-    let (output, filename) = get_filename(&args);
+    let data = pack(
+        &rom,
+        args.calc.clone(),
+        args.variant,
+        &args.folder,
+        &filename,
+        args.desc.as_deref().unwrap_or(""),
+    )?;
 
-    let mut rom = File::open(&args.file)?;
-    let mut storage = Vec::new();
-    rom.read_to_end(&mut storage)?;
+    File::create(output)?.write_all(&data)
+}
 
-    if storage.len() > 0x1000 {
-        return Err(Error::from(ErrorKind::InvalidData));
-    }
+fn write_group(args: &Args) -> Result<(), Error> {
+    let mut roms = Vec::with_capacity(args.files.len());
 
-    let mut header_storage = [0u8; 91]; // sizeof(ti_header)
+    for file in &args.files {
+        let rom = read_rom(file)?;
+        check_rom_size(file, &rom, args.variant)?;
+        if args.verify {
+            verify_round_trip(&rom)?;
+        }
+        roms.push((group_entry_name(args, file)?, rom));
+    }
 
-    let storage = compress(storage);
+    let entries: Vec<(&str, &[u8])> = roms
+        .iter()
+        .map(|(name, rom)| (name.as_str(), rom.as_slice()))
+        .collect();
 
-    fill_header(
-        ch8_header::View::new(&mut header_storage),
-        args.calc,
+    let data = pack_group(
+        &entries,
+        args.calc.clone(),
+        args.variant,
         &args.folder,
-        &filename,
-        storage.len(),
-        "ch8".len(),
+        args.desc.as_deref().unwrap_or(""),
     )?;
 
-    let mut f = File::create(output)?;
-
-    writev(
-        &mut f,
-        &[
-            &header_storage,
-            &storage,
-            &OTH_CH8,
-            &compute_checksum(&[
-                &header_storage[ch8_header::datasize::OFFSET..],
-                &storage,
-                &OTH_CH8,
-            ]),
-        ],
-    )
+    File::create(get_group_filename(args)?)?.write_all(&data)
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+
+    if args.group {
+        write_group(&args)
+    } else if args.files.len() == 1 {
+        write_single(&args)
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "multiple ROMs were given without --group",
+        ))
+    }
 }